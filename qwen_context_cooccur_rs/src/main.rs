@@ -1,21 +1,54 @@
 use std::collections::{BTreeSet, HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use bzip2::read::BzDecoder;
+use clap::{Parser, ValueEnum};
+use crossbeam_queue::ArrayQueue;
+use flate2::read::GzDecoder;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use scroll::{Pwrite, LE};
 use serde::Deserialize;
 use tokenizers::{PaddingParams, Tokenizer};
 use walkdir::WalkDir;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdWriteEncoder;
+
+/// Extensions (beyond plain `.jsonl`) recognised as compressed jsonl shards.
+const COMPRESSED_JSONL_SUFFIXES: &[&str] = &[".jsonl.gz", ".jsonl.zst", ".jsonl.bz2", ".jsonl.xz"];
+
+/// Magic bytes identifying the binary CSR adjacency format.
+const CSR_MAGIC: &[u8; 4] = b"QCSR";
+const CSR_VERSION: u32 = 1;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutFormat {
+    /// Decimal adjacency list, `src\tdst dst dst ...` per line
+    Tsv,
+    /// Binary CSR graph: header, `u64` offsets array, flat `u32` neighbour array
+    Csr,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum WeightMetric {
+    /// Raw co-occurrence count
+    Count,
+    /// Positive pointwise mutual information: `max(0, log(count_ab * N / (count_a * count_b)))`
+    Pmi,
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "qwen_context_cooccur_rs", version, about = "Build token co-occurrence mapping using a HuggingFace tokenizer.json", long_about = None)]
 struct Cli {
-    /// Directory containing .jsonl files (one object per line, a 'text' field is expected)
+    /// Directory containing .jsonl files (one object per line, a 'text' field is expected);
+    /// .jsonl.gz, .jsonl.zst, .jsonl.bz2, and .jsonl.xz shards are decompressed on the fly
     #[arg(long = "data-dir", value_name = "DIR", value_parser)]
     data_dir: PathBuf,
 
@@ -38,6 +71,42 @@ struct Cli {
     /// Path to save the adjacency list TSV file
     #[arg(long = "out-file", value_name = "FILE", value_parser)]
     out_file: PathBuf,
+
+    /// Spill intermediate (src, neighbour) pairs to per-bucket temp files under this
+    /// directory instead of holding the full adjacency map in memory
+    #[arg(long = "spill-dir", value_name = "DIR", value_parser)]
+    spill_dir: Option<PathBuf>,
+
+    /// Number of buckets to split accumulation into when --spill-dir is set; peak memory
+    /// is roughly 1/num-buckets of the in-memory mode
+    #[arg(long = "num-buckets", default_value_t = 16)]
+    num_buckets: usize,
+
+    /// Output format: decimal TSV adjacency list, or compact binary CSR
+    #[arg(long = "out-format", value_enum, default_value = "tsv")]
+    out_format: OutFormat,
+
+    /// Stream the output through a zstd encoder
+    #[arg(long = "compress-out")]
+    compress_out: bool,
+
+    /// Capacity of the bounded queue between tokenizer and merge threads (in-memory
+    /// mode only); tune against --batch-size to cap peak memory
+    #[arg(long = "queue-depth", default_value_t = 1024)]
+    queue_depth: usize,
+
+    /// Number of dedicated merge threads (and adjacency shards) in in-memory mode
+    #[arg(long = "merge-threads", default_value_t = 4)]
+    merge_threads: usize,
+
+    /// Track per-pair co-occurrence counts instead of a boolean adjacency set, and
+    /// emit `src\tdst:weight dst:weight ...` rows (not compatible with --out-format csr)
+    #[arg(long = "weighted")]
+    weighted: bool,
+
+    /// Edge weight to emit in --weighted mode
+    #[arg(long = "weight-metric", value_enum, default_value = "count")]
+    weight_metric: WeightMetric,
 }
 
 #[derive(Deserialize)]
@@ -46,7 +115,13 @@ struct JsonLine {
     text: String,
 }
 
-fn accumulate_pairs(token_ids: &[u32], ctx_len: usize, adj: &mut HashMap<u32, HashSet<u32>>) {
+/// A token's set of co-occurring neighbour tokens, keyed by token id.
+type AdjacencyMap = HashMap<u32, HashSet<u32>>;
+
+/// Like `AdjacencyMap`, but each neighbour carries its co-occurrence count.
+type WeightedAdjacencyMap = HashMap<u32, HashMap<u32, u32>>;
+
+fn accumulate_pairs(token_ids: &[u32], ctx_len: usize, adj: &mut AdjacencyMap) {
     if token_ids.len() < 2 || ctx_len < 2 {
         return;
     }
@@ -68,15 +143,215 @@ fn accumulate_pairs(token_ids: &[u32], ctx_len: usize, adj: &mut HashMap<u32, Ha
     }
 }
 
+/// Same windowing as `accumulate_pairs`, but counts co-occurrences and tallies
+/// per-token `marginals` and `total_windows`.
+fn accumulate_pairs_weighted(
+    token_ids: &[u32],
+    ctx_len: usize,
+    adj: &mut WeightedAdjacencyMap,
+    marginals: &mut HashMap<u32, u64>,
+    total_windows: &mut u64,
+) {
+    if token_ids.len() < 2 || ctx_len < 2 {
+        return;
+    }
+    let mut start = 0;
+    while start < token_ids.len() {
+        let end = (start + ctx_len).min(token_ids.len());
+        let segment = &token_ids[start..end];
+        if segment.len() >= 2 {
+            let mut uniq: HashSet<u32> = HashSet::with_capacity(segment.len());
+            for &id in segment {
+                uniq.insert(id);
+            }
+            for &token in &uniq {
+                let counts = adj.entry(token).or_default();
+                for &neighbour in &uniq {
+                    *counts.entry(neighbour).or_insert(0) += 1;
+                }
+            }
+            for &token in &uniq {
+                *marginals.entry(token).or_insert(0) += 1;
+            }
+            *total_windows += 1;
+        }
+        start += ctx_len;
+    }
+}
+
+fn merge_weighted_adjacency(global: &mut WeightedAdjacencyMap, local: WeightedAdjacencyMap) {
+    for (token, counts) in local {
+        let entry = global.entry(token).or_default();
+        for (neighbour, count) in counts {
+            *entry.entry(neighbour).or_insert(0) += count;
+        }
+    }
+}
+
+fn merge_marginals(global: &mut HashMap<u32, u64>, local: HashMap<u32, u64>) {
+    for (token, count) in local {
+        *global.entry(token).or_insert(0) += count;
+    }
+}
+
+/// Per-bucket temp files used by the two-pass spill mode.
+struct SpillBuckets {
+    dir: PathBuf,
+    bucket_size: u32,
+    writers: Vec<Mutex<BufWriter<File>>>,
+}
+
+impl SpillBuckets {
+    fn create(dir: &Path, num_buckets: usize, vocab_size: usize) -> Result<Self> {
+        let num_buckets = num_buckets.max(1);
+        fs::create_dir_all(dir).with_context(|| format!("Create spill dir {:?}", dir))?;
+        let bucket_size = (vocab_size.max(1) as u64).div_ceil(num_buckets as u64).max(1) as u32;
+        let mut writers = Vec::with_capacity(num_buckets);
+        for idx in 0..num_buckets {
+            let file = File::create(Self::bucket_path(dir, idx))
+                .with_context(|| format!("Create bucket file {} in {:?}", idx, dir))?;
+            writers.push(Mutex::new(BufWriter::new(file)));
+        }
+        Ok(Self { dir: dir.to_path_buf(), bucket_size, writers })
+    }
+
+    fn bucket_path(dir: &Path, idx: usize) -> PathBuf {
+        dir.join(format!("bucket-{idx:04}.bin"))
+    }
+
+    fn bucket_of(&self, token: u32) -> usize {
+        ((token / self.bucket_size) as usize).min(self.writers.len() - 1)
+    }
+
+    fn write_pair(&self, src: u32, dst: u32) -> Result<()> {
+        let mut writer = self.writers[self.bucket_of(src)]
+            .lock()
+            .map_err(|_| anyhow::anyhow!("bucket writer mutex poisoned"))?;
+        writer.write_all(&src.to_le_bytes())?;
+        writer.write_all(&dst.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn flush_all(&self) -> Result<()> {
+        for writer in &self.writers {
+            writer
+                .lock()
+                .map_err(|_| anyhow::anyhow!("bucket writer mutex poisoned"))?
+                .flush()?;
+        }
+        Ok(())
+    }
+
+    fn cleanup(&self) {
+        for idx in 0..self.writers.len() {
+            let _ = fs::remove_file(Self::bucket_path(&self.dir, idx));
+        }
+    }
+}
+
+/// Same windowing as `accumulate_pairs`, but writes pairs to `buckets` instead.
+fn accumulate_pairs_spill(token_ids: &[u32], ctx_len: usize, buckets: &SpillBuckets) -> Result<()> {
+    if token_ids.len() < 2 || ctx_len < 2 {
+        return Ok(());
+    }
+    let mut start = 0;
+    while start < token_ids.len() {
+        let end = (start + ctx_len).min(token_ids.len());
+        let segment = &token_ids[start..end];
+        if segment.len() >= 2 {
+            let mut uniq: HashSet<u32> = HashSet::with_capacity(segment.len());
+            for &id in segment {
+                uniq.insert(id);
+            }
+            for &token in &uniq {
+                for &neighbour in &uniq {
+                    buckets.write_pair(token, neighbour)?;
+                }
+            }
+        }
+        start += ctx_len;
+    }
+    Ok(())
+}
+
+/// Loads a single bucket's raw pair records into a local adjacency map.
+fn load_bucket(path: &Path) -> Result<AdjacencyMap> {
+    let mut file = File::open(path).with_context(|| format!("Open bucket file {:?}", path))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let mut adj: AdjacencyMap = HashMap::new();
+    for chunk in buf.chunks_exact(8) {
+        let src = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+        let dst = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+        adj.entry(src).or_insert_with(HashSet::new).insert(dst);
+    }
+    Ok(adj)
+}
+
+/// Same windowing as `accumulate_pairs_spill`, but also tallies `marginals`
+/// and `total_windows`.
+fn accumulate_pairs_spill_weighted(
+    token_ids: &[u32],
+    ctx_len: usize,
+    buckets: &SpillBuckets,
+    marginals: &mut HashMap<u32, u64>,
+    total_windows: &mut u64,
+) -> Result<()> {
+    if token_ids.len() < 2 || ctx_len < 2 {
+        return Ok(());
+    }
+    let mut start = 0;
+    while start < token_ids.len() {
+        let end = (start + ctx_len).min(token_ids.len());
+        let segment = &token_ids[start..end];
+        if segment.len() >= 2 {
+            let mut uniq: HashSet<u32> = HashSet::with_capacity(segment.len());
+            for &id in segment {
+                uniq.insert(id);
+            }
+            for &token in &uniq {
+                for &neighbour in &uniq {
+                    buckets.write_pair(token, neighbour)?;
+                }
+            }
+            for &token in &uniq {
+                *marginals.entry(token).or_insert(0) += 1;
+            }
+            *total_windows += 1;
+        }
+        start += ctx_len;
+    }
+    Ok(())
+}
+
+/// Loads a single bucket's raw pair records into per-pair co-occurrence counts.
+fn load_bucket_counts(path: &Path) -> Result<WeightedAdjacencyMap> {
+    let mut file = File::open(path).with_context(|| format!("Open bucket file {:?}", path))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let mut adj: WeightedAdjacencyMap = HashMap::new();
+    for chunk in buf.chunks_exact(8) {
+        let src = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+        let dst = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+        *adj.entry(src).or_default().entry(dst).or_insert(0) += 1;
+    }
+    Ok(adj)
+}
+
 fn collect_jsonl_files(data_dir: &Path) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     for entry in WalkDir::new(data_dir) {
         let entry = entry?;
         if entry.file_type().is_file() {
-            if let Some(ext) = entry.path().extension() {
-                if ext == "jsonl" {
-                    files.push(entry.into_path());
-                }
+            let is_jsonl = entry.path().extension().is_some_and(|ext| ext == "jsonl");
+            let is_compressed_jsonl = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| COMPRESSED_JSONL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)));
+            if is_jsonl || is_compressed_jsonl {
+                files.push(entry.into_path());
             }
         }
     }
@@ -84,6 +359,24 @@ fn collect_jsonl_files(data_dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
+/// Wraps `file` in a decompressing reader chosen by sniffing its leading magic bytes.
+fn open_decoded(file: File) -> Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(file);
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(reader))))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(BufReader::new(ZstdDecoder::new(reader)?)))
+    } else if magic.starts_with(&[0x42, 0x5a, 0x68]) {
+        Ok(Box::new(BufReader::new(BzDecoder::new(reader))))
+    } else if magic.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a]) {
+        Ok(Box::new(BufReader::new(XzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
 fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
     // If a directory is given, append tokenizer.json
     let tok_path = if path.is_dir() {
@@ -103,7 +396,7 @@ fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
 
 fn read_jsonl_texts(path: &Path) -> Result<Vec<String>> {
     let file = File::open(path).with_context(|| format!("Open file {:?}", path))?;
-    let reader = BufReader::new(file);
+    let reader = open_decoded(file).with_context(|| format!("Open decoder for {:?}", path))?;
     let mut texts = Vec::new();
     for line in reader.lines() {
         let line = line?;
@@ -117,7 +410,7 @@ fn read_jsonl_texts(path: &Path) -> Result<Vec<String>> {
     Ok(texts)
 }
 
-fn process_batch(tokenizer: &Tokenizer, texts: &[String], ctx_len: usize) -> Result<HashMap<u32, HashSet<u32>>> {
+fn process_batch(tokenizer: &Tokenizer, texts: &[String], ctx_len: usize) -> Result<AdjacencyMap> {
     let encodings = tokenizer
         .encode_batch(
             texts.iter().map(|t| t.as_str()).collect::<Vec<_>>(),
@@ -125,7 +418,7 @@ fn process_batch(tokenizer: &Tokenizer, texts: &[String], ctx_len: usize) -> Res
         )
         .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
 
-    let mut local_adj: HashMap<u32, HashSet<u32>> = HashMap::new();
+    let mut local_adj: AdjacencyMap = HashMap::new();
     for enc in encodings {
         let ids = enc.get_ids();
         if !ids.is_empty() {
@@ -135,15 +428,38 @@ fn process_batch(tokenizer: &Tokenizer, texts: &[String], ctx_len: usize) -> Res
     Ok(local_adj)
 }
 
-fn merge_adjacency(global: &mut HashMap<u32, HashSet<u32>>, local: HashMap<u32, HashSet<u32>>) {
+fn merge_adjacency(global: &mut AdjacencyMap, local: AdjacencyMap) {
     for (k, vset) in local.into_iter() {
         let entry = global.entry(k).or_insert_with(HashSet::new);
         entry.extend(vset);
     }
 }
 
-fn write_adjacency(out_file: &Path, adj: &HashMap<u32, HashSet<u32>>) -> Result<()> {
-    let mut file = File::create(out_file).with_context(|| format!("Create {:?}", out_file))?;
+/// Weighted counterpart of `process_batch`: also returns marginal counts and
+/// the total window count.
+fn process_batch_weighted(
+    tokenizer: &Tokenizer,
+    texts: &[String],
+    ctx_len: usize,
+) -> Result<(WeightedAdjacencyMap, HashMap<u32, u64>, u64)> {
+    let encodings = tokenizer
+        .encode_batch(texts.iter().map(|t| t.as_str()).collect::<Vec<_>>(), false)
+        .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+
+    let mut local_adj: WeightedAdjacencyMap = HashMap::new();
+    let mut local_marginals: HashMap<u32, u64> = HashMap::new();
+    let mut local_windows = 0u64;
+    for enc in encodings {
+        let ids = enc.get_ids();
+        if !ids.is_empty() {
+            accumulate_pairs_weighted(ids, ctx_len, &mut local_adj, &mut local_marginals, &mut local_windows);
+        }
+    }
+    Ok((local_adj, local_marginals, local_windows))
+}
+
+/// Writes one adjacency map's rows, in ascending token-id order, to `writer`.
+fn write_adjacency_rows<W: Write>(writer: &mut W, adj: &AdjacencyMap) -> Result<()> {
     let mut keys: Vec<u32> = adj.keys().copied().collect();
     keys.sort_unstable();
 
@@ -156,12 +472,602 @@ fn write_adjacency(out_file: &Path, adj: &HashMap<u32, HashSet<u32>>) -> Result<
                 .map(|n| n.to_string())
                 .collect::<Vec<_>>()
                 .join(" ");
-            writeln!(file, "{}\t{}", token_id, joined)?;
+            writeln!(writer, "{}\t{}", token_id, joined)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_adjacency(out_file: &Path, adj: &AdjacencyMap) -> Result<()> {
+    let mut file = File::create(out_file).with_context(|| format!("Create {:?}", out_file))?;
+    write_adjacency_rows(&mut file, adj)
+}
+
+/// Writes one weighted adjacency map's rows, in ascending token-id order, as
+/// `src\tdst:weight dst:weight ...`.
+fn write_adjacency_weighted<W: Write>(
+    writer: &mut W,
+    adj: &WeightedAdjacencyMap,
+    marginals: &HashMap<u32, u64>,
+    total_windows: u64,
+    metric: WeightMetric,
+) -> Result<()> {
+    let mut keys: Vec<u32> = adj.keys().copied().collect();
+    keys.sort_unstable();
+
+    for token_id in keys {
+        let counts = &adj[&token_id];
+        let mut neighbours: Vec<u32> = counts.keys().copied().filter(|&n| n != token_id).collect();
+        neighbours.sort_unstable();
+
+        let joined = neighbours
+            .iter()
+            .map(|&n| {
+                let count = counts[&n];
+                match metric {
+                    WeightMetric::Count => format!("{}:{}", n, count),
+                    WeightMetric::Pmi => format!("{}:{:.4}", n, pointwise_mutual_info(count, token_id, n, marginals, total_windows)),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(writer, "{}\t{}", token_id, joined)?;
+    }
+    Ok(())
+}
+
+/// Positive PMI: `max(0, log(count_ab * N / (count_a * count_b)))`.
+fn pointwise_mutual_info(count_ab: u32, token_a: u32, token_b: u32, marginals: &HashMap<u32, u64>, total_windows: u64) -> f64 {
+    let count_a = *marginals.get(&token_a).unwrap_or(&0) as f64;
+    let count_b = *marginals.get(&token_b).unwrap_or(&0) as f64;
+    if count_a == 0.0 || count_b == 0.0 || total_windows == 0 {
+        return 0.0;
+    }
+    ((count_ab as f64 * total_windows as f64) / (count_a * count_b)).ln().max(0.0)
+}
+
+/// An output sink that's either a plain file or a zstd-compressing wrapper around one.
+enum SinkWriter {
+    Plain(File),
+    Zstd(ZstdWriteEncoder<'static, File>),
+}
+
+impl SinkWriter {
+    fn create(out_file: &Path, compress: bool) -> Result<Self> {
+        let file = File::create(out_file).with_context(|| format!("Create {:?}", out_file))?;
+        if compress {
+            Ok(SinkWriter::Zstd(ZstdWriteEncoder::new(file, 0)?))
+        } else {
+            Ok(SinkWriter::Plain(file))
+        }
+    }
+
+    /// Finalizes the zstd frame (a no-op for the plain file sink).
+    fn finish(self) -> Result<()> {
+        match self {
+            SinkWriter::Plain(mut file) => Ok(file.flush()?),
+            SinkWriter::Zstd(encoder) => {
+                encoder.finish()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for SinkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SinkWriter::Plain(file) => file.write(buf),
+            SinkWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SinkWriter::Plain(file) => file.flush(),
+            SinkWriter::Zstd(encoder) => encoder.flush(),
         }
     }
+}
+
+/// Writes the CSR header (magic + version + token count) and the `u64` offsets array.
+fn write_csr_header<W: Write>(writer: &mut W, token_count: u64, offsets: &[u64]) -> Result<()> {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(CSR_MAGIC);
+    header.pwrite_with::<u32>(CSR_VERSION, 4, LE)?;
+    header.pwrite_with::<u64>(token_count, 8, LE)?;
+    writer.write_all(&header)?;
+
+    let mut offsets_buf = vec![0u8; offsets.len() * 8];
+    for (i, &offset) in offsets.iter().enumerate() {
+        offsets_buf.pwrite_with::<u64>(offset, i * 8, LE)?;
+    }
+    writer.write_all(&offsets_buf)?;
     Ok(())
 }
 
+/// Writes the full adjacency map as a binary CSR graph: row `token_id` owns
+/// `neighbours[offsets[token_id]..offsets[token_id + 1]]`.
+fn write_adjacency_csr(out_file: &Path, adj: &AdjacencyMap, vocab_size: usize, compress: bool) -> Result<()> {
+    let token_count = vocab_size as u64;
+    let mut offsets: Vec<u64> = Vec::with_capacity(vocab_size + 1);
+    offsets.push(0);
+    let mut neighbours: Vec<u32> = Vec::new();
+    for token_id in 0..vocab_size as u32 {
+        let mut list: Vec<u32> = adj
+            .get(&token_id)
+            .map(|neigh| neigh.iter().copied().filter(|&n| n != token_id).collect())
+            .unwrap_or_default();
+        list.sort_unstable();
+        neighbours.extend_from_slice(&list);
+        offsets.push(neighbours.len() as u64);
+    }
+
+    let mut sink = SinkWriter::create(out_file, compress)?;
+    write_csr_header(&mut sink, token_count, &offsets)?;
+    for n in neighbours {
+        sink.write_all(&n.to_le_bytes())?;
+    }
+    sink.finish()
+}
+
+/// Spill-mode counterpart of `write_adjacency_csr`: resolves one bucket at a time,
+/// staging neighbour rows in a temp file since the final size isn't known until
+/// every bucket has been visited.
+fn write_adjacency_csr_spill(out_file: &Path, spill_dir: &Path, num_buckets: usize, vocab_size: usize, compress: bool) -> Result<()> {
+    let staged_path = spill_dir.join("csr-neighbours.tmp");
+
+    let result = (|| -> Result<()> {
+        let mut counts = vec![0u64; vocab_size];
+
+        {
+            let mut staged = BufWriter::new(
+                File::create(&staged_path).with_context(|| format!("Create {:?}", staged_path))?,
+            );
+            for idx in 0..num_buckets {
+                let local_adj = load_bucket(&SpillBuckets::bucket_path(spill_dir, idx))?;
+                let mut token_ids: Vec<u32> = local_adj.keys().copied().collect();
+                token_ids.sort_unstable();
+                for token_id in token_ids {
+                    let mut list: Vec<u32> = local_adj[&token_id].iter().copied().filter(|&n| n != token_id).collect();
+                    list.sort_unstable();
+                    counts[token_id as usize] = list.len() as u64;
+                    for n in list {
+                        staged.write_all(&n.to_le_bytes())?;
+                    }
+                }
+            }
+            staged.flush()?;
+        }
+
+        let mut offsets: Vec<u64> = Vec::with_capacity(vocab_size + 1);
+        offsets.push(0);
+        for &count in &counts {
+            offsets.push(offsets.last().unwrap() + count);
+        }
+
+        let mut sink = SinkWriter::create(out_file, compress)?;
+        write_csr_header(&mut sink, vocab_size as u64, &offsets)?;
+        let mut staged = File::open(&staged_path).with_context(|| format!("Open {:?}", staged_path))?;
+        std::io::copy(&mut staged, &mut sink)?;
+        sink.finish()
+    })();
+
+    let _ = fs::remove_file(&staged_path);
+    result
+}
+
+/// Bounded producer-consumer pipeline: rayon reader/tokenizer threads push each
+/// batch's local result onto a fixed-capacity queue, and a pool of merge threads
+/// drains it and folds entries into `num_shards` shards via `merge`. The queue's
+/// fixed capacity is the backpressure: once it's full, producers wait in
+/// `push_with_backpressure` until a merge thread drains it. Shared by `run_in_memory` and `run_weighted`'s
+/// in-memory path.
+fn run_pipeline<T, S>(
+    args: &Cli,
+    jsonl_files: &[PathBuf],
+    m: &MultiProgress,
+    num_shards: usize,
+    shard_init: impl Fn() -> S,
+    produce: impl Fn(&Path, &[String]) -> Option<Result<T>> + Sync,
+    merge: impl Fn(&[Mutex<S>], T) -> Result<()> + Sync,
+) -> Result<Vec<S>>
+where
+    T: Send,
+    S: Send,
+{
+    let shards: Arc<Vec<Mutex<S>>> = Arc::new((0..num_shards).map(|_| Mutex::new(shard_init())).collect());
+    let queue: Arc<ArrayQueue<T>> = Arc::new(ArrayQueue::new(args.queue_depth.max(1)));
+    let producers_done = Arc::new(AtomicBool::new(false));
+    let records_processed = Arc::new(AtomicU64::new(0));
+
+    let files_pb = m.add(ProgressBar::new(jsonl_files.len() as u64));
+    files_pb.set_style(ProgressStyle::with_template("{spinner} Files {pos}/{len} [{elapsed_precise}] {wide_msg}").unwrap());
+    let queue_pb = m.add(ProgressBar::new_spinner());
+    queue_pb.set_style(ProgressStyle::with_template("{spinner} {wide_msg}").unwrap());
+
+    std::thread::scope(|scope| -> Result<()> {
+        let merge_handles: Vec<_> = (0..args.merge_threads.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let shards = Arc::clone(&shards);
+                let producers_done = Arc::clone(&producers_done);
+                let merge = &merge;
+                scope.spawn(move || -> Result<()> {
+                    while let Some(local) = pop_or_wait(&queue, &producers_done) {
+                        merge(&shards, local)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        jsonl_files.par_iter().for_each(|path| {
+            let _ = files_pb.inc(1);
+            files_pb.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+
+            let texts = match read_jsonl_texts(path) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Failed to read {:?}: {}", path, e);
+                    return;
+                }
+            };
+            if texts.is_empty() {
+                return;
+            }
+
+            let mut start = 0usize;
+            while start < texts.len() {
+                let end = (start + args.batch_size).min(texts.len());
+                let batch = &texts[start..end];
+                start = end;
+
+                match produce(path, batch) {
+                    Some(Ok(local)) => {
+                        records_processed.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                        push_with_backpressure(&queue, local);
+                    }
+                    Some(Err(e)) => eprintln!("Tokenization failed for file {:?}: {}", path, e),
+                    None => {}
+                }
+                queue_pb.set_message(format!(
+                    "Records {} | queue {}/{}",
+                    records_processed.load(Ordering::Relaxed),
+                    queue.len(),
+                    args.queue_depth.max(1)
+                ));
+            }
+        });
+
+        producers_done.store(true, Ordering::Release);
+        for handle in merge_handles {
+            handle.join().map_err(|_| anyhow::anyhow!("Merge thread panicked"))??;
+        }
+        Ok(())
+    })?;
+
+    let shards = Arc::try_unwrap(shards).map_err(|_| anyhow::anyhow!("Arc unwrap failed; outstanding refs exist"))?;
+    shards
+        .into_iter()
+        .map(|shard| shard.into_inner().map_err(|_| anyhow::anyhow!("shard mutex poisoned")))
+        .collect()
+}
+
+fn run_in_memory(args: &Cli, jsonl_files: &[PathBuf], tokenizer: Arc<Tokenizer>, m: &MultiProgress) -> Result<()> {
+    let num_shards = args.merge_threads.max(1);
+    let tok = Arc::clone(&tokenizer);
+    let shards = run_pipeline::<AdjacencyMap, AdjacencyMap>(
+        args,
+        jsonl_files,
+        m,
+        num_shards,
+        HashMap::new,
+        move |_path, batch| match process_batch(&tok, batch, args.context_length) {
+            Ok(local) if !local.is_empty() => Some(Ok(local)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        },
+        |shards, local: AdjacencyMap| -> Result<()> {
+            for (token, neighbours) in local {
+                let mut guard = shards[token as usize % shards.len()]
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("shard mutex poisoned"))?;
+                guard.entry(token).or_default().extend(neighbours);
+            }
+            Ok(())
+        },
+    )?;
+
+    let mut adj: AdjacencyMap = HashMap::new();
+    for shard in shards {
+        merge_adjacency(&mut adj, shard);
+    }
+
+    match args.out_format {
+        OutFormat::Tsv if !args.compress_out => write_adjacency(&args.out_file, &adj),
+        OutFormat::Tsv => {
+            let mut sink = SinkWriter::create(&args.out_file, true)?;
+            write_adjacency_rows(&mut sink, &adj)?;
+            sink.finish()
+        }
+        OutFormat::Csr => write_adjacency_csr(&args.out_file, &adj, tokenizer.get_vocab_size(true), args.compress_out),
+    }
+}
+
+/// Pops from the queue, or blocks briefly and retries while producers are still
+/// running; returns `None` once producers are done and the queue is drained.
+fn pop_or_wait<T>(queue: &ArrayQueue<T>, producers_done: &AtomicBool) -> Option<T> {
+    loop {
+        if let Some(local) = queue.pop() {
+            return Some(local);
+        }
+        if producers_done.load(Ordering::Acquire) {
+            return queue.pop();
+        }
+        std::thread::sleep(Duration::from_micros(200));
+    }
+}
+
+/// Pushes onto the bounded queue, blocking (with a short backoff sleep) while it's full.
+fn push_with_backpressure<T>(queue: &ArrayQueue<T>, mut local: T) {
+    while let Err(returned) = queue.push(local) {
+        local = returned;
+        std::thread::sleep(Duration::from_micros(200));
+    }
+}
+
+/// Memory-bounded two-pass mode: spills `(src, dst)` pairs to per-bucket temp
+/// files, then resolves and writes one bucket at a time.
+fn run_spill(
+    args: &Cli,
+    jsonl_files: &[PathBuf],
+    tokenizer: Arc<Tokenizer>,
+    spill_dir: &Path,
+    m: &MultiProgress,
+) -> Result<()> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let buckets = SpillBuckets::create(spill_dir, args.num_buckets, vocab_size)?;
+
+    let files_pb = m.add(ProgressBar::new(jsonl_files.len() as u64));
+    files_pb.set_style(ProgressStyle::with_template("{spinner} Files {pos}/{len} [{elapsed_precise}] {wide_msg}").unwrap());
+
+    let result = (|| -> Result<()> {
+        let pair_errors: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        jsonl_files.par_iter().try_for_each(|path| -> Result<()> {
+            let _ = files_pb.inc(1);
+            files_pb.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+
+            let texts = match read_jsonl_texts(path) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Failed to read {:?}: {}", path, e);
+                    return Ok(());
+                }
+            };
+            if texts.is_empty() {
+                return Ok(());
+            }
+
+            let mut start = 0usize;
+            while start < texts.len() {
+                let end = (start + args.batch_size).min(texts.len());
+                let batch = &texts[start..end];
+                start = end;
+
+                let encodings = match tokenizer.encode_batch(batch.iter().map(|t| t.as_str()).collect::<Vec<_>>(), false) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        eprintln!("Tokenization failed for file {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                for enc in encodings {
+                    let ids = enc.get_ids();
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = accumulate_pairs_spill(ids, args.context_length, &buckets) {
+                        pair_errors
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("pair error log mutex poisoned"))?
+                            .push(format!("{:?}: {}", path, e));
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        if let Some(msg) = pair_errors
+            .into_inner()
+            .map_err(|_| anyhow::anyhow!("pair error log mutex poisoned"))?
+            .into_iter()
+            .next()
+        {
+            anyhow::bail!("Failed to spill pairs for {}", msg);
+        }
+        buckets.flush_all()?;
+
+        // Pass two: resolve one bucket at a time (its rows are already in the right
+        // global order, since buckets own contiguous token-id ranges) and write it out.
+        match args.out_format {
+            OutFormat::Tsv => {
+                let mut sink = SinkWriter::create(&args.out_file, args.compress_out)?;
+                for idx in 0..args.num_buckets {
+                    let local_adj = load_bucket(&SpillBuckets::bucket_path(spill_dir, idx))?;
+                    write_adjacency_rows(&mut sink, &local_adj)?;
+                }
+                sink.finish()
+            }
+            OutFormat::Csr => write_adjacency_csr_spill(&args.out_file, spill_dir, args.num_buckets, vocab_size, args.compress_out),
+        }
+    })();
+
+    buckets.cleanup();
+    result
+}
+
+/// `--weighted` mode: tracks per-pair co-occurrence counts plus per-token marginals
+/// and a total window count, so the writer can emit raw counts or PMI. Reuses
+/// `SpillBuckets`' raw pair records when `--spill-dir` is set; otherwise runs
+/// through the same bounded pipeline as `run_in_memory`, sharded per token.
+fn run_weighted(args: &Cli, jsonl_files: &[PathBuf], tokenizer: Arc<Tokenizer>, spill_dir: Option<&Path>, m: &MultiProgress) -> Result<()> {
+    match spill_dir {
+        Some(spill_dir) => {
+            let files_pb = m.add(ProgressBar::new(jsonl_files.len() as u64));
+            files_pb.set_style(ProgressStyle::with_template("{spinner} Files {pos}/{len} [{elapsed_precise}] {wide_msg}").unwrap());
+
+            let marginals: Arc<Mutex<HashMap<u32, u64>>> = Arc::new(Mutex::new(HashMap::new()));
+            let total_windows = Arc::new(AtomicU64::new(0));
+            let vocab_size = tokenizer.get_vocab_size(true);
+            let buckets = SpillBuckets::create(spill_dir, args.num_buckets, vocab_size)?;
+
+            let result = (|| -> Result<()> {
+                let pair_errors: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+                jsonl_files.par_iter().try_for_each(|path| -> Result<()> {
+                    let _ = files_pb.inc(1);
+                    files_pb.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+
+                    let texts = match read_jsonl_texts(path) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            eprintln!("Failed to read {:?}: {}", path, e);
+                            return Ok(());
+                        }
+                    };
+                    if texts.is_empty() {
+                        return Ok(());
+                    }
+
+                    let mut start = 0usize;
+                    while start < texts.len() {
+                        let end = (start + args.batch_size).min(texts.len());
+                        let batch = &texts[start..end];
+                        start = end;
+
+                        let encodings = match tokenizer.encode_batch(batch.iter().map(|t| t.as_str()).collect::<Vec<_>>(), false) {
+                            Ok(e) => e,
+                            Err(e) => {
+                                eprintln!("Tokenization failed for file {:?}: {}", path, e);
+                                continue;
+                            }
+                        };
+
+                        let mut local_marginals: HashMap<u32, u64> = HashMap::new();
+                        let mut local_windows = 0u64;
+                        for enc in encodings {
+                            let ids = enc.get_ids();
+                            if ids.is_empty() {
+                                continue;
+                            }
+                            if let Err(e) = accumulate_pairs_spill_weighted(ids, args.context_length, &buckets, &mut local_marginals, &mut local_windows) {
+                                pair_errors
+                                    .lock()
+                                    .map_err(|_| anyhow::anyhow!("pair error log mutex poisoned"))?
+                                    .push(format!("{:?}: {}", path, e));
+                            }
+                        }
+                        if !local_marginals.is_empty() {
+                            let mut guard = marginals
+                                .lock()
+                                .map_err(|_| anyhow::anyhow!("marginals mutex poisoned"))?;
+                            merge_marginals(&mut guard, local_marginals);
+                        }
+                        total_windows.fetch_add(local_windows, Ordering::Relaxed);
+                    }
+                    Ok(())
+                })?;
+
+                if let Some(msg) = pair_errors
+                    .into_inner()
+                    .map_err(|_| anyhow::anyhow!("pair error log mutex poisoned"))?
+                    .into_iter()
+                    .next()
+                {
+                    anyhow::bail!("Failed to spill pairs for {}", msg);
+                }
+                buckets.flush_all()?;
+
+                let marginals = Arc::try_unwrap(marginals)
+                    .map_err(|_| anyhow::anyhow!("Arc unwrap failed; outstanding refs exist"))?
+                    .into_inner()
+                    .map_err(|_| anyhow::anyhow!("marginals mutex poisoned"))?;
+                let total_windows = total_windows.load(Ordering::Relaxed);
+
+                let mut sink = SinkWriter::create(&args.out_file, args.compress_out)?;
+                for idx in 0..args.num_buckets {
+                    let local_counts = load_bucket_counts(&SpillBuckets::bucket_path(spill_dir, idx))?;
+                    write_adjacency_weighted(&mut sink, &local_counts, &marginals, total_windows, args.weight_metric)?;
+                }
+                sink.finish()
+            })();
+
+            buckets.cleanup();
+            result
+        }
+        None => {
+            type WeightedShard = (WeightedAdjacencyMap, HashMap<u32, u64>);
+
+            let num_shards = args.merge_threads.max(1);
+            let tok = Arc::clone(&tokenizer);
+            let ctx_len = args.context_length;
+            let total_windows = Arc::new(AtomicU64::new(0));
+            let total_windows_producer = Arc::clone(&total_windows);
+
+            let shards = run_pipeline::<WeightedShard, WeightedShard>(
+                args,
+                jsonl_files,
+                m,
+                num_shards,
+                || (HashMap::new(), HashMap::new()),
+                move |_path, batch| match process_batch_weighted(&tok, batch, ctx_len) {
+                    Ok((adj, marginals, windows)) => {
+                        total_windows_producer.fetch_add(windows, Ordering::Relaxed);
+                        if adj.is_empty() && marginals.is_empty() {
+                            None
+                        } else {
+                            Some(Ok((adj, marginals)))
+                        }
+                    }
+                    Err(e) => Some(Err(e)),
+                },
+                |shards, (adj, marginals): WeightedShard| -> Result<()> {
+                    for (token, counts) in adj {
+                        let mut guard = shards[token as usize % shards.len()]
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("shard mutex poisoned"))?;
+                        let entry = guard.0.entry(token).or_default();
+                        for (neighbour, count) in counts {
+                            *entry.entry(neighbour).or_insert(0) += count;
+                        }
+                    }
+                    for (token, count) in marginals {
+                        let mut guard = shards[token as usize % shards.len()]
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("shard mutex poisoned"))?;
+                        *guard.1.entry(token).or_insert(0) += count;
+                    }
+                    Ok(())
+                },
+            )?;
+
+            let mut adj: WeightedAdjacencyMap = HashMap::new();
+            let mut marginals: HashMap<u32, u64> = HashMap::new();
+            for (shard_adj, shard_marginals) in shards {
+                merge_weighted_adjacency(&mut adj, shard_adj);
+                merge_marginals(&mut marginals, shard_marginals);
+            }
+            let total_windows = total_windows.load(Ordering::Relaxed);
+
+            let mut sink = SinkWriter::create(&args.out_file, args.compress_out)?;
+            write_adjacency_weighted(&mut sink, &adj, &marginals, total_windows, args.weight_metric)?;
+            sink.finish()
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args = Cli::parse();
 
@@ -170,6 +1076,10 @@ fn main() -> Result<()> {
         .build_global()
         .ok();
 
+    if args.weighted && args.out_format == OutFormat::Csr {
+        anyhow::bail!("--weighted does not support --out-format csr; use the default tsv format");
+    }
+
     let jsonl_files = collect_jsonl_files(&args.data_dir)?;
     if jsonl_files.is_empty() {
         anyhow::bail!("No .jsonl files found under {:?}", args.data_dir);
@@ -187,61 +1097,111 @@ fn main() -> Result<()> {
     let tokenizer = load_tokenizer(&args.tokenizer_path)?;
     let tokenizer = Arc::new(tokenizer);
 
-    // Shared adjacency map with mutex; use local maps per batch and merge
-    let global_adj: Arc<Mutex<HashMap<u32, HashSet<u32>>>> = Arc::new(Mutex::new(HashMap::new()));
-
     // Progress bars
     let m = MultiProgress::new();
-    let files_pb = m.add(ProgressBar::new(jsonl_files.len() as u64));
-    files_pb.set_style(ProgressStyle::with_template("{spinner} Files {pos}/{len} [{elapsed_precise}] {wide_msg}").unwrap());
-
-    // Iterate files in parallel (safe: tokenizer is Send+Sync)
-    jsonl_files.par_iter().for_each(|path| {
-        let _ = files_pb.inc(1);
-        files_pb.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
 
-        // Read all lines' texts first; then process in batches
-        let texts = match read_jsonl_texts(path) {
-            Ok(t) => t,
-            Err(e) => {
-                eprintln!("Failed to read {:?}: {}", path, e);
-                return;
-            }
-        };
-        if texts.is_empty() {
-            return;
+    if args.weighted {
+        run_weighted(&args, &jsonl_files, tokenizer, args.spill_dir.as_deref(), &m)?;
+    } else {
+        match &args.spill_dir {
+            Some(spill_dir) => run_spill(&args, &jsonl_files, tokenizer, spill_dir, &m)?,
+            None => run_in_memory(&args, &jsonl_files, tokenizer, &m)?,
         }
+    }
 
-        let mut start = 0usize;
-        while start < texts.len() {
-            let end = (start + args.batch_size).min(texts.len());
-            let batch = &texts[start..end];
-            start = end;
+    println!("Adjacency list saved to {}", args.out_file.canonicalize().unwrap_or(args.out_file.clone()).display());
 
-            let local = match process_batch(&tokenizer, batch, args.context_length) {
-                Ok(m) => m,
-                Err(e) => {
-                    eprintln!("Tokenization failed for file {:?}: {}", path, e);
-                    continue;
-                }
-            };
+    Ok(())
+}
 
-            if !local.is_empty() {
-                if let Ok(mut guard) = global_adj.lock() {
-                    merge_adjacency(&mut guard, local);
-                }
-            }
-        }
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Write output
-    let adj = Arc::try_unwrap(global_adj)
-        .map_err(|_| anyhow::anyhow!("Arc unwrap failed; outstanding refs exist"))?
-        .into_inner()
-        .map_err(|_| anyhow::anyhow!("Mutex poisoned"))?;
-    write_adjacency(&args.out_file, &adj)?;
+    fn unique_temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qwen_context_cooccur_rs_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-    println!("Adjacency list saved to {}", args.out_file.canonicalize().unwrap_or(args.out_file.clone()).display());
+    // token 0 -> {1, 2}, token 1 -> {0}, token 2 -> {}; vocab_size 3, so the
+    // expected CSR layout is offsets [0, 2, 3, 3] and neighbours [1, 2, 0].
+    fn small_adjacency() -> AdjacencyMap {
+        let mut adj: AdjacencyMap = HashMap::new();
+        adj.insert(0, HashSet::from([1, 2]));
+        adj.insert(1, HashSet::from([0]));
+        adj
+    }
 
-    Ok(())
+    fn assert_csr_bytes(bytes: &[u8]) {
+        assert_eq!(&bytes[0..4], CSR_MAGIC);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), CSR_VERSION);
+        assert_eq!(u64::from_le_bytes(bytes[8..16].try_into().unwrap()), 3);
+
+        let offsets: Vec<u64> = bytes[16..48]
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, vec![0, 2, 3, 3]);
+
+        let neighbours: Vec<u32> = bytes[48..60]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(neighbours, vec![1, 2, 0]);
+
+        assert_eq!(bytes.len(), 60);
+    }
+
+    #[test]
+    fn write_adjacency_csr_round_trips_header_offsets_and_neighbours() {
+        let dir = unique_temp_dir("csr");
+        let out_file = dir.join("out.csr");
+
+        write_adjacency_csr(&out_file, &small_adjacency(), 3, false).unwrap();
+
+        let bytes = fs::read(&out_file).unwrap();
+        assert_csr_bytes(&bytes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_adjacency_csr_spill_round_trips_header_offsets_and_neighbours() {
+        let dir = unique_temp_dir("csr_spill");
+        let spill_dir = dir.join("buckets");
+        let out_file = dir.join("out.csr");
+
+        let buckets = SpillBuckets::create(&spill_dir, 2, 3).unwrap();
+        buckets.write_pair(0, 1).unwrap();
+        buckets.write_pair(0, 2).unwrap();
+        buckets.write_pair(1, 0).unwrap();
+        buckets.flush_all().unwrap();
+
+        write_adjacency_csr_spill(&out_file, &spill_dir, 2, 3, false).unwrap();
+
+        let bytes = fs::read(&out_file).unwrap();
+        assert_csr_bytes(&bytes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pointwise_mutual_info_matches_hand_computed_value() {
+        // count_ab=3, count_a=10, count_b=5, total_windows=100:
+        // ln(3 * 100 / (10 * 5)) = ln(6) ~= 1.791759469
+        let marginals = HashMap::from([(1u32, 10u64), (2u32, 5u64)]);
+        let pmi = pointwise_mutual_info(3, 1, 2, &marginals, 100);
+        assert!((pmi - 6f64.ln()).abs() < 1e-9, "expected ln(6) ~= {}, got {}", 6f64.ln(), pmi);
+    }
+
+    #[test]
+    fn pointwise_mutual_info_clamps_negative_to_zero() {
+        // count_ab=1, count_a=100, count_b=100, total_windows=1: ratio << 1, so the
+        // raw log is negative and must be clamped to 0 rather than returned as-is.
+        let marginals = HashMap::from([(1u32, 100u64), (2u32, 100u64)]);
+        let pmi = pointwise_mutual_info(1, 1, 2, &marginals, 1);
+        assert_eq!(pmi, 0.0);
+    }
 }